@@ -10,8 +10,39 @@ use ordermap::OrderMap;
 use rand::{self, Rng};
 use std::{cmp, net};
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use tokio_core::reactor::Handle;
 
+/// Smoothing factor applied to each completion's RTT sample. Fixed (rather than derived
+/// from wall-clock gaps between samples) so that a burst of completions drained in a
+/// single poll tick still each move the EWMA instead of being discarded as "too soon".
+const EWMA_ALPHA: f32 = 0.2;
+
+/// Assumed RTT for an endpoint that has not yet completed a request, so that outstanding
+/// in-flight connections affect `load` (and thus P2C selection) before the first sample.
+const DEFAULT_EWMA_RTT_SECS: f32 = 1.0;
+
+/// Floor applied to `weight` in the `load` divisor so a zero or negative weight can't
+/// produce a NaN, ∞ or negative load. Any of those would skew the `ep0.load <= ep1.load`
+/// comparison in `Manager::select_endpoint`: a NaN makes it false whichever side the NaN
+/// is on, and a negative load always wins it.
+const MIN_WEIGHT: f32 = 1e-6;
+
+fn secs(d: Duration) -> f32 {
+    d.as_secs() as f32 + (d.subsec_nanos() as f32 / 1e9)
+}
+
+/// Folds one latency sample into an EWMA.
+fn ewma_fold(prev: f32, sample: f32) -> f32 {
+    prev + EWMA_ALPHA * (sample - prev)
+}
+
+/// `load = ewma_rtt * (outstanding + 1) / weight`, floored so a non-positive `weight`
+/// can't produce a NaN/∞ result.
+fn endpoint_load(ewma_rtt: f32, outstanding: usize, weight: f32) -> f32 {
+    ewma_rtt * (outstanding as f32 + 1.0) / weight.max(MIN_WEIGHT)
+}
+
 pub fn new(dst: Path,
            reactor: Handle,
            conn: Connector,
@@ -148,6 +179,26 @@ impl Manager {
                     }
                 }
             }
+
+            // Reap completion signals for connections that were established but never
+            // dispatched (e.g. closed while idle in the pool). They never contributed to
+            // `outstanding`/`load`, so there's nothing to fold back in here.
+            for _ in 0..ep.completing.len() {
+                let mut fut = ep.completing.pop_front().unwrap();
+                if let Ok(Async::NotReady) = fut.poll() {
+                    ep.completing.push_back(fut);
+                }
+            }
+
+            // Fold completions of dispatched connections into the endpoint's load signal.
+            for _ in 0..ep.dispatched.len() {
+                let (started, mut fut) = ep.dispatched.pop_front().unwrap();
+                match fut.poll() {
+                    Ok(Async::NotReady) => ep.dispatched.push_back((started, fut)),
+                    Ok(Async::Ready(_)) | Err(_) => ep.complete(started),
+                }
+            }
+
             summary.pending += ep.connecting.len();;
             summary.connected += ep.connected.len();
         }
@@ -254,6 +305,9 @@ struct Endpoint {
     weight: f32,
     load: f32,
 
+    /// Exponentially-weighted moving average of measured dispatch latency, in seconds.
+    ewma_rtt: f32,
+
     /// Queues pending connections that have not yet been completed.
     connecting: VecDeque<Connecting>,
 
@@ -263,13 +317,17 @@ struct Endpoint {
     /// Queues dispatch requests for connections.
     dispatchees: VecDeque<Dispatchee>,
 
-    /// Holds a future that will be completed when streaming is complete.
-    ///
-    /// ## XXX
-    ///
-    /// This shold be replaced with a notification-aware data structure so that all items
-    /// are not polled regularly (so that balancers can scale to 100K+ connections).
+    /// Completion signals for established connections that have not yet been dispatched,
+    /// kept in lockstep with `connected` (both are pushed together in `mk_ctx`'s caller,
+    /// and popped together in `dispatch`). Resolving here means a pooled connection was
+    /// closed before ever serving a request, so it never affected `load`.
     completing: VecDeque<Completing>,
+
+    /// Completion signals for dispatched connections, paired with the `Instant` dispatch
+    /// happened. `dispatched.len()` is the "outstanding" count the request asks for
+    /// (dispatched-but-not-completed), and the paired `Instant` lets `complete` measure
+    /// real dispatch latency itself rather than trusting a `Summary` payload field.
+    dispatched: VecDeque<(Instant, Completing)>,
 }
 
 impl Endpoint {
@@ -278,20 +336,46 @@ impl Endpoint {
             dst_name: dst,
             peer_addr: addr,
             weight: weight,
-            load: ::std::f32::MAX,
+            load: endpoint_load(DEFAULT_EWMA_RTT_SECS, 0, weight),
+            ewma_rtt: DEFAULT_EWMA_RTT_SECS,
             connecting: VecDeque::default(),
             connected: VecDeque::default(),
             dispatchees: VecDeque::default(),
             completing: VecDeque::default(),
+            dispatched: VecDeque::default(),
         }
     }
 
+    /// Builds a `DstCtx` for a newly established connection and queues its completion
+    /// signal in `completing`, to be promoted to `dispatched` once actually handed to a
+    /// dispatchee (see `dispatch`). `minimum_connections` keeps spare connections
+    /// established ahead of demand, so counting them here would inflate `load` with
+    /// connections that aren't serving anything.
     fn mk_ctx(&mut self, local_addr: net::SocketAddr) -> DstCtx {
         let (tx, rx) = oneshot::channel();
         self.completing.push_back(rx);
         DstCtx::new(self.dst_name.clone(), local_addr, self.peer_addr, tx)
     }
 
+    /// Recomputes `load` from the current EWMA RTT and outstanding (dispatched but not
+    /// yet completed) count, so that heavier endpoints (higher `weight`) absorb
+    /// proportionally more of the dispatched load.
+    fn update_load(&mut self) {
+        self.load = endpoint_load(self.ewma_rtt, self.dispatched.len(), self.weight);
+    }
+
+    /// Folds one dispatch's measured latency into the endpoint's RTT EWMA and refreshes
+    /// `load`. `started` is when the connection was handed to a dispatchee; the elapsed
+    /// time to this call (completion or drop, either way the connection is done with it)
+    /// is the real dispatch latency, measured here rather than read from the resolved
+    /// `Summary` itself, so this doesn't depend on `DstCtx`/`Summary` (defined elsewhere
+    /// in the crate) populating any particular field.
+    fn complete(&mut self, started: Instant) {
+        let sample = secs(started.elapsed());
+        self.ewma_rtt = ewma_fold(self.ewma_rtt, sample);
+        self.update_load();
+    }
+
     fn is_idle(&self) -> bool {
         // XXX this should
         self.connecting.is_empty() && self.dispatchees.is_empty()
@@ -315,6 +399,9 @@ impl Endpoint {
                 if let Err(conn) = d.send(conn) {
                     // Dispatchee no longer waiting. save the connection for later.
                     self.connected.push_front(conn);
+                } else if let Some(completing) = self.completing.pop_front() {
+                    self.dispatched.push_back((Instant::now(), completing));
+                    self.update_load();
                 }
             }
         }
@@ -360,4 +447,49 @@ struct ConnectionPollSummary {
     connected: usize,
     dispatched: usize,
     failed: usize,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ewma_fold, endpoint_load, EWMA_ALPHA, DEFAULT_EWMA_RTT_SECS, MIN_WEIGHT};
+
+    #[test]
+    fn ewma_fold_moves_partway_toward_the_sample() {
+        let prev = 1.0;
+        let next = ewma_fold(prev, 2.0);
+        assert_eq!(next, prev + EWMA_ALPHA * (2.0 - prev));
+        assert!(prev < next && next < 2.0);
+    }
+
+    #[test]
+    fn endpoint_load_scales_with_outstanding_and_weight() {
+        let idle = endpoint_load(2.0, 0, 1.0);
+        assert_eq!(idle, 2.0);
+
+        let loaded = endpoint_load(2.0, 3, 1.0);
+        assert_eq!(loaded, 2.0 * 4.0);
+
+        // Doubling weight halves load for the same ewma_rtt/outstanding.
+        let heavier = endpoint_load(2.0, 3, 2.0);
+        assert_eq!(heavier, loaded / 2.0);
+    }
+
+    #[test]
+    fn endpoint_load_floors_non_positive_weight() {
+        assert!(endpoint_load(0.0, 0, 0.0).is_finite());
+
+        let load = endpoint_load(1.0, 0, -5.0);
+        assert!(load.is_finite());
+        assert_eq!(load, 1.0 / MIN_WEIGHT);
+    }
+
+    #[test]
+    fn cold_start_load_is_nonzero_and_outstanding_aware() {
+        // Matches Endpoint::new's initial load: a fresh endpoint with no samples yet
+        // still reflects outstanding connections, rather than pinning to 0.0 and always
+        // winning select_endpoint's `ep0.load <= ep1.load` comparison regardless of load.
+        let cold = endpoint_load(DEFAULT_EWMA_RTT_SECS, 0, 1.0);
+        assert!(cold > 0.0);
+        assert!(endpoint_load(DEFAULT_EWMA_RTT_SECS, 1, 1.0) > cold);
+    }
+}